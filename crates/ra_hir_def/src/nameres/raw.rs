@@ -13,6 +13,7 @@ use hir_expand::{
     name::{AsName, Name},
 };
 use ra_arena::{Arena, Idx};
+use ra_cfg::{CfgExpr, CfgOptions};
 use ra_prof::profile;
 use ra_syntax::{
     ast::{self, AttrsOwner, NameOwner, VisibilityOwner},
@@ -38,7 +39,9 @@ pub struct RawItems {
     imports: Arena<ImportData>,
     defs: Arena<DefData>,
     macros: Arena<MacroData>,
+    macro_defs: Arena<MacroDefData>,
     impls: Arena<ImplData>,
+    traits: Arena<TraitData>,
     /// items for top-level module
     items: Vec<RawItem>,
 }
@@ -46,11 +49,27 @@ pub struct RawItems {
 impl RawItems {
     pub(crate) fn raw_items_query(db: &dyn DefDatabase, file_id: HirFileId) -> Arc<RawItems> {
         let _p = profile("raw_items_query");
+        // The set of enabled `cfg` options is read through the (salsa-tracked)
+        // crate graph, so raw items stay edit-stable while still recomputing
+        // when the options change.
+        //
+        // `raw_items` is keyed on `file_id` alone, but a file can be included by
+        // several crates with conflicting `cfg`s. There is no single correct set
+        // of options in that case, so we only gate items when the file belongs to
+        // exactly one crate and otherwise keep every item (the pre-cfg behaviour),
+        // leaving genuinely per-crate gating to the crate-local def map.
+        let crate_graph = db.upcast().crate_graph();
+        let crates = db.upcast().relevant_crates(file_id.original_file(db.upcast()));
+        let cfg_options = match crates.iter().next() {
+            Some(&krate) if crates.len() == 1 => Some(crate_graph[krate].cfg_options.clone()),
+            _ => None,
+        };
         let mut collector = RawItemsCollector {
             raw_items: RawItems::default(),
             source_ast_id_map: db.ast_id_map(file_id),
             file_id,
             hygiene: Hygiene::new(db.upcast(), file_id),
+            cfg_options,
         };
         if let Some(node) = db.parse_or_expand(file_id) {
             if let Some(source_file) = ast::SourceFile::cast(node.clone()) {
@@ -96,6 +115,13 @@ impl Index<Idx<MacroData>> for RawItems {
     }
 }
 
+impl Index<Idx<MacroDefData>> for RawItems {
+    type Output = MacroDefData;
+    fn index(&self, idx: Idx<MacroDefData>) -> &MacroDefData {
+        &self.macro_defs[idx]
+    }
+}
+
 impl Index<Idx<ImplData>> for RawItems {
     type Output = ImplData;
     fn index(&self, idx: Idx<ImplData>) -> &ImplData {
@@ -103,6 +129,13 @@ impl Index<Idx<ImplData>> for RawItems {
     }
 }
 
+impl Index<Idx<TraitData>> for RawItems {
+    type Output = TraitData;
+    fn index(&self, idx: Idx<TraitData>) -> &TraitData {
+        &self.traits[idx]
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub(super) struct RawItem {
     pub(super) attrs: Attrs,
@@ -115,7 +148,9 @@ pub(super) enum RawItemKind {
     Import(Import),
     Def(Idx<DefData>),
     Macro(Idx<MacroData>),
+    MacroDef(Idx<MacroDefData>),
     Impl(Idx<ImplData>),
+    Trait(Idx<TraitData>),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -170,7 +205,6 @@ pub(super) enum DefKind {
     Enum(FileAstId<ast::EnumDef>),
     Const(FileAstId<ast::ConstDef>),
     Static(FileAstId<ast::StaticDef>),
-    Trait(FileAstId<ast::TraitDef>),
     TypeAlias(FileAstId<ast::TypeAliasDef>),
 }
 
@@ -183,7 +217,6 @@ impl DefKind {
             DefKind::Enum(it) => it.upcast(),
             DefKind::Const(it) => it.upcast(),
             DefKind::Static(it) => it.upcast(),
-            DefKind::Trait(it) => it.upcast(),
             DefKind::TypeAlias(it) => it.upcast(),
         }
     }
@@ -199,9 +232,54 @@ pub(super) struct MacroData {
     pub(super) builtin: bool,
 }
 
+/// A macro *definition*, as opposed to a [`MacroData`] invocation. A definition
+/// introduces a named item into scope, so name resolution can treat it as such
+/// instead of inferring it from an exported call.
+///
+/// Today this covers `macro_rules! foo { .. }` (`macro_rules` is `true`). The
+/// flag is here so the new-style `macro foo { .. }` item can be represented the
+/// same way once the grammar surfaces it as its own node.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct MacroDefData {
+    pub(super) ast_id: FileAstId<ast::MacroCall>,
+    pub(super) path: ModPath,
+    pub(super) name: Name,
+    pub(super) macro_rules: bool,
+    pub(super) export: bool,
+    pub(super) local_inner: bool,
+    pub(super) builtin: bool,
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub(super) struct ImplData {
     pub(super) ast_id: FileAstId<ast::ImplDef>,
+    pub(super) items: Vec<RawItem>,
+}
+
+impl ImplData {
+    /// The impl's associated functions, consts and type aliases as raw items.
+    /// Consumed by the module collector (`nameres/collector.rs`) when it lowers
+    /// the impl so that members are resolved as proper items.
+    pub(super) fn items(&self) -> &[RawItem] {
+        &self.items
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct TraitData {
+    pub(super) name: Name,
+    pub(super) visibility: RawVisibility,
+    pub(super) ast_id: FileAstId<ast::TraitDef>,
+    pub(super) items: Vec<RawItem>,
+}
+
+impl TraitData {
+    /// The trait's associated items as raw items, in declaration order.
+    /// Consumed by the module collector (`nameres/collector.rs`) when it lowers
+    /// the trait.
+    pub(super) fn items(&self) -> &[RawItem] {
+        &self.items
+    }
 }
 
 struct RawItemsCollector {
@@ -209,6 +287,10 @@ struct RawItemsCollector {
     source_ast_id_map: Arc<AstIdMap>,
     file_id: HirFileId,
     hygiene: Hygiene,
+    /// The active cfg options, or `None` when the file does not belong to
+    /// exactly one crate (it is shared by several crates, or by none) and we
+    /// therefore must not gate on any single crate's options.
+    cfg_options: Option<CfgOptions>,
 }
 
 impl RawItemsCollector {
@@ -224,6 +306,11 @@ impl RawItemsCollector {
 
     fn add_item(&mut self, current_module: Option<Idx<ModuleData>>, item: ast::ModuleItem) {
         let attrs = self.parse_attrs(&item);
+        // Items gated behind a `cfg` that is not active are never compiled, so
+        // they must not reach name resolution at all.
+        if !self.is_cfg_enabled(&attrs) {
+            return;
+        }
         let visibility = RawVisibility::from_ast_with_hygiene(item.visibility(), &self.hygiene);
         let (kind, name) = match item {
             ast::ModuleItem::Module(module) => {
@@ -264,7 +351,8 @@ impl RawItemsCollector {
                 (DefKind::Function(self.source_ast_id_map.ast_id(&it)), it.name())
             }
             ast::ModuleItem::TraitDef(it) => {
-                (DefKind::Trait(self.source_ast_id_map.ast_id(&it)), it.name())
+                self.add_trait(current_module, it);
+                return;
             }
             ast::ModuleItem::TypeAliasDef(it) => {
                 (DefKind::TypeAlias(self.source_ast_id_map.ast_id(&it)), it.name())
@@ -299,6 +387,9 @@ impl RawItemsCollector {
         if let Some(items) = block.extern_item_list() {
             for item in items.extern_items() {
                 let attrs = self.parse_attrs(&item);
+                if !self.is_cfg_enabled(&attrs) {
+                    continue;
+                }
                 let visibility =
                     RawVisibility::from_ast_with_hygiene(item.visibility(), &self.hygiene);
                 let (kind, name) = match item {
@@ -350,9 +441,8 @@ impl RawItemsCollector {
     }
 
     fn add_use_item(&mut self, current_module: Option<Idx<ModuleData>>, use_item: ast::UseItem) {
-        // FIXME: cfg_attr
-        let is_prelude = use_item.has_atom_attr("prelude_import");
         let attrs = self.parse_attrs(&use_item);
+        let is_prelude = self.has_cfg_enabled_attr(&attrs, "prelude_import");
         let visibility = RawVisibility::from_ast_with_hygiene(use_item.visibility(), &self.hygiene);
 
         let mut buf = Vec::new();
@@ -390,8 +480,7 @@ impl RawItemsCollector {
                 a.name().map(|it| it.as_name()).map_or(ImportAlias::Underscore, ImportAlias::Alias)
             });
             let attrs = self.parse_attrs(&extern_crate);
-            // FIXME: cfg_attr
-            let is_macro_use = extern_crate.has_atom_attr("macro_use");
+            let is_macro_use = self.has_cfg_enabled_attr(&attrs, "macro_use");
             let import_data = ImportData {
                 path,
                 alias,
@@ -415,10 +504,9 @@ impl RawItemsCollector {
         let name = m.name().map(|it| it.as_name());
         let ast_id = self.source_ast_id_map.ast_id(&m);
 
-        // FIXME: cfg_attr
         let export_attr = attrs.by_key("macro_export");
 
-        let export = export_attr.exists();
+        let export = self.has_cfg_enabled_attr(&attrs, "macro_export");
         let local_inner = if export {
             export_attr.tt_values().map(|it| &it.token_trees).flatten().any(|it| match it {
                 tt::TokenTree::Leaf(tt::Leaf::Ident(ident)) => {
@@ -432,6 +520,26 @@ impl RawItemsCollector {
 
         let builtin = attrs.by_key("rustc_builtin_macro").exists();
 
+        // A named `macro_rules!` call is a macro *definition* rather than an
+        // invocation; give it its own kind so resolution scopes the name
+        // instead of inferring it from the exported call.
+        let macro_rules = path.as_ident().map_or(false, |it| it.to_string() == "macro_rules");
+        if macro_rules {
+            if let Some(name) = name {
+                let def = self.raw_items.macro_defs.alloc(MacroDefData {
+                    ast_id,
+                    path,
+                    name,
+                    macro_rules,
+                    export,
+                    local_inner,
+                    builtin,
+                });
+                self.push_item(current_module, attrs, RawItemKind::MacroDef(def));
+                return;
+            }
+        }
+
         let m = self.raw_items.macros.alloc(MacroData {
             ast_id,
             path,
@@ -446,10 +554,60 @@ impl RawItemsCollector {
     fn add_impl(&mut self, current_module: Option<Idx<ModuleData>>, imp: ast::ImplDef) {
         let attrs = self.parse_attrs(&imp);
         let ast_id = self.source_ast_id_map.ast_id(&imp);
-        let imp = self.raw_items.impls.alloc(ImplData { ast_id });
+        let items =
+            imp.item_list().map_or_else(Vec::new, |items| self.lower_assoc_items(&items));
+        let imp = self.raw_items.impls.alloc(ImplData { ast_id, items });
         self.push_item(current_module, attrs, RawItemKind::Impl(imp))
     }
 
+    fn add_trait(&mut self, current_module: Option<Idx<ModuleData>>, trait_def: ast::TraitDef) {
+        let name = match trait_def.name() {
+            Some(it) => it.as_name(),
+            None => return,
+        };
+        let attrs = self.parse_attrs(&trait_def);
+        let visibility =
+            RawVisibility::from_ast_with_hygiene(trait_def.visibility(), &self.hygiene);
+        let ast_id = self.source_ast_id_map.ast_id(&trait_def);
+        let items =
+            trait_def.item_list().map_or_else(Vec::new, |items| self.lower_assoc_items(&items));
+        let t = self.raw_items.traits.alloc(TraitData { name, visibility, ast_id, items });
+        self.push_item(current_module, attrs, RawItemKind::Trait(t));
+    }
+
+    /// Lowers the associated functions, consts and type aliases of an impl or
+    /// trait into raw items, walking the item list the same way
+    /// [`process_module`] walks a module. Returning the members as their own
+    /// `RawItem`s keeps an edit inside one member body from invalidating its
+    /// siblings.
+    ///
+    /// [`process_module`]: RawItemsCollector::process_module
+    fn lower_assoc_items(&mut self, item_list: &ast::ItemList) -> Vec<RawItem> {
+        item_list.impl_items().filter_map(|item| self.lower_assoc_item(&item)).collect()
+    }
+
+    fn lower_assoc_item(&mut self, item: &ast::ImplItem) -> Option<RawItem> {
+        let attrs = self.parse_attrs(item);
+        if !self.is_cfg_enabled(&attrs) {
+            return None;
+        }
+        let (kind, name, visibility) = match item {
+            ast::ImplItem::FnDef(it) => {
+                (DefKind::Function(self.source_ast_id_map.ast_id(it)), it.name(), it.visibility())
+            }
+            ast::ImplItem::ConstDef(it) => {
+                (DefKind::Const(self.source_ast_id_map.ast_id(it)), it.name(), it.visibility())
+            }
+            ast::ImplItem::TypeAliasDef(it) => {
+                (DefKind::TypeAlias(self.source_ast_id_map.ast_id(it)), it.name(), it.visibility())
+            }
+        };
+        let name = name?.as_name();
+        let visibility = RawVisibility::from_ast_with_hygiene(visibility, &self.hygiene);
+        let def = self.raw_items.defs.alloc(DefData { name, kind, visibility });
+        Some(RawItem { attrs, kind: RawItemKind::Def(def) })
+    }
+
     fn push_import(
         &mut self,
         current_module: Option<Idx<ModuleData>>,
@@ -479,4 +637,101 @@ impl RawItemsCollector {
     fn parse_attrs(&self, item: &impl ast::AttrsOwner) -> Attrs {
         Attrs::new(item, &self.hygiene)
     }
+
+    /// Evaluates every `#[cfg(..)]` predicate attached to `attrs` against the
+    /// active crate options, returning `false` as soon as one of them is known
+    /// to be disabled. A `#[cfg_attr(guard, cfg(..))]` contributes its inner
+    /// `cfg`s whenever its guard holds. A predicate for which
+    /// `CfgOptions::check` gives no answer (`None`) is treated as enabled,
+    /// matching the behaviour of name resolution elsewhere; when the file does
+    /// not belong to exactly one crate we do not gate at all (see
+    /// `raw_items_query`).
+    fn is_cfg_enabled(&self, attrs: &Attrs) -> bool {
+        let cfg_options = match &self.cfg_options {
+            Some(it) => it,
+            None => return true,
+        };
+        let holds = |pred: &tt::Subtree| cfg_options.check(&CfgExpr::parse(pred)) != Some(false);
+
+        if !attrs.by_key("cfg").tt_values().all(|pred| holds(pred)) {
+            return false;
+        }
+        for tt in attrs.by_key("cfg_attr").tt_values() {
+            if let Some((guard, spliced)) = split_cfg_attr(tt) {
+                if !holds(&guard) {
+                    continue;
+                }
+                if spliced.iter().filter_map(cfg_attr_inner_cfg).any(|pred| !holds(&pred)) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// `true` if the `key` attribute (e.g. `macro_export`) is attached directly
+    /// or spliced in by a `#[cfg_attr(guard, key)]` whose guard predicate holds.
+    ///
+    /// When the file is shared by several crates we have no options to check the
+    /// guard against, so a `cfg_attr`-guarded attribute is reported as absent
+    /// (matching the baseline) rather than over-approximated as present for
+    /// every crate.
+    fn has_cfg_enabled_attr(&self, attrs: &Attrs, key: &str) -> bool {
+        if attrs.by_key(key).exists() {
+            return true;
+        }
+        let cfg_options = match &self.cfg_options {
+            Some(it) => it,
+            None => return false,
+        };
+        attrs.by_key("cfg_attr").tt_values().any(|tt| match split_cfg_attr(tt) {
+            Some((guard, spliced)) => {
+                cfg_options.check(&CfgExpr::parse(&guard)) != Some(false)
+                    && spliced.iter().any(|a| attr_name(a).as_deref() == Some(key))
+            }
+            None => false,
+        })
+    }
+}
+
+/// Splits a `#[cfg_attr(guard, a, b, ..)]` token tree into the guard predicate
+/// and the token trees of the attributes it would splice in. Returns `None` if
+/// the token tree is empty.
+fn split_cfg_attr(input: &tt::Subtree) -> Option<(tt::Subtree, Vec<tt::Subtree>)> {
+    if input.token_trees.is_empty() {
+        return None;
+    }
+    let mut groups = input.token_trees.split(|tt| {
+        matches!(tt, tt::TokenTree::Leaf(tt::Leaf::Punct(p)) if p.char == ',')
+    });
+    let guard = as_subtree(groups.next()?);
+    let spliced = groups.filter(|g| !g.is_empty()).map(as_subtree).collect();
+    Some((guard, spliced))
+}
+
+fn as_subtree(token_trees: &[tt::TokenTree]) -> tt::Subtree {
+    tt::Subtree { delimiter: None, token_trees: token_trees.to_vec() }
+}
+
+/// The leading identifier of an attribute token tree, i.e. `macro_use` in
+/// `macro_use` or `cfg` in `cfg(unix)`.
+fn attr_name(attr: &tt::Subtree) -> Option<String> {
+    match attr.token_trees.first() {
+        Some(tt::TokenTree::Leaf(tt::Leaf::Ident(ident))) => Some(ident.text.to_string()),
+        _ => None,
+    }
+}
+
+/// The predicate of a spliced `cfg(..)` attribute, ready to feed to
+/// [`CfgExpr::parse`]; `None` for any other attribute.
+fn cfg_attr_inner_cfg(attr: &tt::Subtree) -> Option<tt::Subtree> {
+    let mut trees = attr.token_trees.iter();
+    match trees.next() {
+        Some(tt::TokenTree::Leaf(tt::Leaf::Ident(ident))) if ident.text == "cfg" => {}
+        _ => return None,
+    }
+    match trees.next() {
+        Some(tt::TokenTree::Subtree(sub)) => Some(sub.clone()),
+        _ => None,
+    }
 }